@@ -0,0 +1,162 @@
+//! Importer that turns a standard GTFS feed (a `.zip` or an unpacked
+//! directory) into the SQLite schema the viewer queries, so users no longer
+//! have to hand-build the database. The schema itself is owned by the
+//! migration runner in the parent module; this module only fills it.
+
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use csv::StringRecord;
+use rusqlite::{params_from_iter, Connection, Transaction};
+
+use crate::db::run_migrations;
+
+/// One GTFS text file and how its columns map onto a table insert.
+struct Table {
+    file: &'static str,
+    insert: &'static str,
+    // Source column names, in the order the insert's `?n` placeholders expect.
+    columns: &'static [&'static str],
+}
+
+const TABLES: &[Table] = &[
+    Table {
+        file: "agency.txt",
+        insert: "INSERT OR REPLACE INTO agency (agency_id, name, timezone) VALUES (?1, ?2, ?3);",
+        columns: &["agency_id", "agency_name", "agency_timezone"],
+    },
+    Table {
+        file: "routes.txt",
+        insert: "INSERT OR REPLACE INTO route (route_id, agency_id, route_type) \
+            VALUES (?1, ?2, ?3);",
+        columns: &["route_id", "agency_id", "route_type"],
+    },
+    Table {
+        file: "stops.txt",
+        insert: "INSERT OR REPLACE INTO stop (stop_id, name) VALUES (?1, ?2);",
+        columns: &["stop_id", "stop_name"],
+    },
+    Table {
+        file: "trips.txt",
+        insert: "INSERT OR REPLACE INTO trip (trip_id, route_id, service_id, short_name, headsign) \
+            VALUES (?1, ?2, ?3, ?4, ?5);",
+        columns: &["trip_id", "route_id", "service_id", "trip_short_name", "trip_headsign"],
+    },
+    Table {
+        file: "stop_times.txt",
+        insert: "INSERT INTO stop_time (trip_id, stop_id, arrival_time, departure_time, stop_sequence) \
+            VALUES (?1, ?2, ?3, ?4, ?5);",
+        columns: &["trip_id", "stop_id", "arrival_time", "departure_time", "stop_sequence"],
+    },
+    Table {
+        file: "calendar.txt",
+        insert: "INSERT OR REPLACE INTO service \
+            (service_id, monday, tuesday, wednesday, thursday, friday, saturday, sunday, start_date, end_date) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10);",
+        columns: &[
+            "service_id", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday",
+            "sunday", "start_date", "end_date",
+        ],
+    },
+    Table {
+        file: "calendar_dates.txt",
+        insert: "INSERT INTO service_exception (service_id, service_date, exception_type) \
+            VALUES (?1, ?2, ?3);",
+        columns: &["service_id", "date", "exception_type"],
+    },
+    Table {
+        file: "frequencies.txt",
+        insert: "INSERT INTO frequency (trip_id, start_time, end_time, headway_secs, exact_times) \
+            VALUES (?1, ?2, ?3, ?4, ?5);",
+        columns: &["trip_id", "start_time", "end_time", "headway_secs", "exact_times"],
+    },
+];
+
+/// Read a GTFS feed at `zip_or_dir` and populate the database at `db_path`,
+/// creating or upgrading the schema first. All rows are inserted inside one
+/// transaction so a failed import adds no data; the schema migrations run
+/// before that transaction and stay applied.
+pub fn import_gtfs(zip_or_dir: &str, db_path: &str) -> Result<(), Box<dyn Error>> {
+    let source = Source::detect(zip_or_dir);
+    let mut db = Connection::open(db_path)?;
+    run_migrations(&db)?;
+
+    let tx = db.transaction()?;
+    for table in TABLES {
+        import_table(&tx, &source, table)?;
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Load a single GTFS file into its table with a prepared statement, skipping
+/// silently when the (optional) file is absent from the feed.
+fn import_table(tx: &Transaction, source: &Source, table: &Table) -> Result<(), Box<dyn Error>> {
+    let bytes = match source.open(table.file)? {
+        Some(bytes) => bytes,
+        None => return Ok(()),
+    };
+
+    let mut reader = csv::Reader::from_reader(bytes.as_slice());
+    let indices = column_indices(reader.headers()?, table.columns);
+
+    let mut stmt = tx.prepare(table.insert)?;
+    for record in reader.records() {
+        let record = record?;
+        let values = indices.iter().map(|i| i.and_then(|i| record.get(i)).unwrap_or(""));
+        stmt.execute(params_from_iter(values))?;
+    }
+
+    Ok(())
+}
+
+/// Resolve each wanted column to its position in the file's header row.
+fn column_indices(headers: &StringRecord, columns: &[&str]) -> Vec<Option<usize>> {
+    columns.iter().map(|c| headers.iter().position(|h| h == *c)).collect()
+}
+
+/// A GTFS feed on disk, either unpacked into a directory or still zipped.
+enum Source {
+    Dir(PathBuf),
+    Zip(PathBuf),
+}
+
+impl Source {
+    fn detect(path: &str) -> Source {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            Source::Dir(path)
+        } else {
+            Source::Zip(path)
+        }
+    }
+
+    /// Read `name` from the feed, returning `None` when the file is not present.
+    fn open(&self, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self {
+            Source::Dir(dir) => {
+                let path = dir.join(name);
+                match path.exists() {
+                    true => Ok(Some(fs::read(path)?)),
+                    false => Ok(None),
+                }
+            }
+            Source::Zip(path) => {
+                let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+                match archive.by_name(name) {
+                    Ok(mut entry) => {
+                        let mut buffer = Vec::new();
+                        entry.read_to_end(&mut buffer)?;
+                        Ok(Some(buffer))
+                    }
+                    Err(zip::result::ZipError::FileNotFound) => Ok(None),
+                    Err(err) => Err(Box::new(err)),
+                }
+            }
+        }
+    }
+}
@@ -1,16 +1,84 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
 
-use chrono::{Duration, NaiveDateTime};
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use prost::Message;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use regex::Regex;
-use rusqlite::{Connection, Result, Row};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OpenFlags, OptionalExtension, Result, Row};
 
-use crate::db::types::{BoardType, Service, ServiceException, Station, Stop, Weekday};
+use crate::db::realtime::{FeedMessage, ScheduleRelationship, StopTimeEvent};
+use crate::db::types::{
+    BoardType, RouteType, Service, ServiceException, Station, Stop, StopFilter, Weekday,
+};
 use crate::db::util::{str_to_date, str_to_dur};
 
+pub mod import;
+mod realtime;
 mod util;
 pub mod types;
 
+/// Ordered schema migrations. Each entry is applied once, in order, and the
+/// applied count is tracked in the `user_version` pragma, so `new` can create
+/// a fresh database or upgrade an older one in place.
+const MIGRATIONS: &[&str] = &[
+    // v1: initial schema expected by SERVICE_QUERY/STOP_QUERY/TRIP_QUERY.
+    "CREATE TABLE IF NOT EXISTS agency ( \
+        agency_id TEXT PRIMARY KEY, \
+        name TEXT \
+    ); \
+    CREATE TABLE IF NOT EXISTS route ( \
+        route_id TEXT PRIMARY KEY, \
+        agency_id TEXT, \
+        route_type INTEGER \
+    ); \
+    CREATE TABLE IF NOT EXISTS service ( \
+        service_id INTEGER PRIMARY KEY, \
+        monday INTEGER, tuesday INTEGER, wednesday INTEGER, thursday INTEGER, \
+        friday INTEGER, saturday INTEGER, sunday INTEGER, \
+        start_date TEXT, end_date TEXT \
+    ); \
+    CREATE TABLE IF NOT EXISTS service_exception ( \
+        service_id INTEGER, service_date TEXT, exception_type INTEGER \
+    ); \
+    CREATE TABLE IF NOT EXISTS trip ( \
+        trip_id INTEGER PRIMARY KEY, \
+        route_id TEXT, service_id INTEGER, short_name TEXT, headsign TEXT \
+    ); \
+    CREATE TABLE IF NOT EXISTS stop ( \
+        stop_id TEXT PRIMARY KEY, name TEXT \
+    ); \
+    CREATE TABLE IF NOT EXISTS stop_time ( \
+        trip_id INTEGER, stop_id TEXT, \
+        arrival_time TEXT, departure_time TEXT, stop_sequence INTEGER \
+    ); \
+    CREATE TABLE IF NOT EXISTS frequency ( \
+        trip_id INTEGER, start_time TEXT, end_time TEXT, \
+        headway_secs INTEGER, exact_times INTEGER \
+    );",
+    // v2: agencies carry a timezone, needed to localize realtime times.
+    "ALTER TABLE agency ADD COLUMN timezone TEXT;",
+];
+
+/// Apply any migrations the database has not yet seen, advancing the
+/// `user_version` pragma as each one succeeds.
+pub(crate) fn run_migrations(db: &Connection) -> Result<(), Box<dyn Error>> {
+    let version: u32 = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        db.execute_batch(step)?;
+        db.pragma_update(None, "user_version", (index + 1) as i64)?;
+    }
+    Ok(())
+}
+
+/// Feeds whose timestamp is older than this many seconds (relative to the
+/// board's query time) are treated as stale and ignored.
+const MAX_REALTIME_AGE_SECS: i64 = 15 * 60;
+
 //region Queries
 const SERVICE_QUERY: &str = "SELECT s.*, se.service_date, se.exception_type \
     FROM service s \
@@ -18,21 +86,32 @@ const SERVICE_QUERY: &str = "SELECT s.*, se.service_date, se.exception_type \
     ON se.service_id = s.service_id;";
 
 const STOP_QUERY: &str = "SELECT \
-    st.arrival_time, st.departure_time, t.trip_id, s.service_id, t.short_name, t.headsign \
+    st.arrival_time, st.departure_time, t.trip_id, s.service_id, t.short_name, t.headsign, \
+    r.route_type, a.agency_id, st.stop_id \
     FROM stop_time st \
     INNER JOIN trip t ON t.trip_id = st.trip_id \
     INNER JOIN service s ON s.service_id = t.service_id \
     INNER JOIN route r ON r.route_id = t.route_id \
     INNER JOIN agency a ON a.agency_id = r.agency_id
-    WHERE st.stop_id LIKE ?1;";
+    WHERE st.stop_id LIKE ?1";
 
 const TRIP_QUERY: &str = "SELECT
-    st.arrival_time, st.departure_time, st.trip_id, 0, '', s.name \
+    st.arrival_time, st.departure_time, st.trip_id, 0, '', s.name, 0, '', st.stop_id \
     FROM stop_time st \
     INNER JOIN stop s on s.stop_id = st.stop_id \
     WHERE st.trip_id = ?1 \
     ORDER BY st.stop_sequence;";
 
+const FREQUENCY_QUERY: &str = "SELECT \
+    trip_id, start_time, end_time, headway_secs, exact_times \
+    FROM frequency;";
+
+const CONNECTION_QUERY: &str = "SELECT \
+    st.trip_id, t.service_id, st.stop_id, st.arrival_time, st.departure_time \
+    FROM stop_time st \
+    INNER JOIN trip t ON t.trip_id = st.trip_id \
+    ORDER BY st.trip_id, st.stop_sequence;";
+
 fn get_station_query(input: &str) -> String {
     let filter = match input.is_empty() {
         true => String::from("'%Hbf' OR name LIKE '%Hauptbahnhof'"),
@@ -44,27 +123,172 @@ fn get_station_query(input: &str) -> String {
         filter
     )
 }
+
+fn get_stop_query(filter: &StopFilter) -> String {
+    let mut query = String::from(STOP_QUERY);
+
+    // Agency filtering is cheap to push into SQL; route types are applied in
+    // the post-query chain where the mapped `RouteType` already lives. The ids
+    // come from request input, so bind them as `?n` parameters rather than
+    // interpolating them into the statement. `?1` is the stop_id pattern, so
+    // the list starts at `?2`.
+    if let Some(agency_ids) = &filter.agency_ids {
+        if !agency_ids.is_empty() {
+            let list = (0..agency_ids.len())
+                .map(|i| format!("?{}", i + 2))
+                .collect::<Vec<String>>()
+                .join(", ");
+            query.push_str(&format!(" AND a.agency_id IN ({})", list));
+        }
+    }
+
+    query.push(';');
+    query
+}
 //endregion
 
+/// A single `frequencies.txt` entry: a trip that repeats on a fixed headway
+/// between `start_time` and `end_time`.
+#[derive(Clone)]
+struct Frequency {
+    start_time: Duration,
+    end_time: Duration,
+    headway: Duration,
+    // 0 = frequency-based (approximate), 1 = schedule-based spacing.
+    exact_times: u8,
+}
+
+/// A predicted time drawn from a GTFS-Realtime `StopTimeEvent`: either an
+/// absolute POSIX-epoch time or a delay relative to the static schedule.
+#[derive(Clone)]
+enum Prediction {
+    Absolute(i64),
+    Delay(Duration),
+}
+
+/// The realtime prediction for one `(trip, stop)` pair.
+#[derive(Clone)]
+struct RealtimeUpdate {
+    arrival: Option<Prediction>,
+    departure: Option<Prediction>,
+    relationship: ScheduleRelationship,
+}
+
+/// A single ride between two consecutive stops of one trip, the atomic unit
+/// scanned by the Connection Scan Algorithm.
+#[derive(Clone)]
+struct Connection {
+    dep_stop: String,
+    arr_stop: String,
+    dep_time: Duration,
+    arr_time: Duration,
+    trip_id: u32,
+    service_id: u16,
+}
+
+#[derive(Clone)]
 pub struct GTFSDatabase {
-    db: Connection,
-    services: HashMap<u16, Service>,
+    pool: Pool<SqliteConnectionManager>,
+    services: Arc<HashMap<u16, Service>>,
+    frequencies: Arc<HashMap<u32, Vec<Frequency>>>,
+    frequency_starts: Arc<HashMap<u32, Duration>>,
+    realtime: HashMap<(u32, String), RealtimeUpdate>,
+    realtime_timestamp: Option<i64>,
+    timezone: Tz,
     time_regex: Regex,
 }
 
 impl GTFSDatabase {
+    /// Open (creating or upgrading the schema) the database at `db_path`.
+    ///
+    /// Hard precondition: `trip_id` and `service_id` must be numeric across the
+    /// feed. They are stored in INTEGER columns and parsed as `u32`/`u16`
+    /// throughout (`fetch_trip`, `apply_realtime`, the `services` map), so a
+    /// feed using arbitrary string ids is not supported — remap those ids to
+    /// integers before importing.
     pub fn new(db_path: &str) -> Result<GTFSDatabase, Box<dyn Error>> {
-        let db = Connection::open(db_path)?;
-        let services = fetch_services(&db)?;
+        let time_regex = Regex::new(r"(?P<hours>\d{1,2}):(?P<minutes>\d{2}):(?P<seconds>\d{2})")?;
+
+        // Run migrations and precompute the lookups over a writable handle,
+        // enabling WAL once so the read-only pool below can share readers.
+        let setup = Connection::open(db_path)?;
+        run_migrations(&setup)?;
+        setup.pragma_update(None, "journal_mode", "WAL")?;
+        let services = fetch_services(&setup)?;
+        let frequencies = fetch_frequencies(&setup, &time_regex)?;
+        let frequency_starts = fetch_frequency_starts(&setup, &time_regex, &frequencies)?;
+        let timezone = fetch_timezone(&setup)?;
+        drop(setup);
+
+        // All queries run against read-only, `query_only` connections so the
+        // database can be shared across threads without write contention.
+        let manager = SqliteConnectionManager::file(db_path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_init(|conn| conn.execute_batch("PRAGMA query_only = true;"));
+        let pool = Pool::new(manager)?;
+
         Ok(GTFSDatabase {
-            db,
-            services,
-            time_regex: Regex::new(r"(?P<hours>\d{1,2}):(?P<minutes>\d{2}):(?P<seconds>\d{2})")?,
+            pool,
+            services: Arc::new(services),
+            frequencies: Arc::new(frequencies),
+            frequency_starts: Arc::new(frequency_starts),
+            realtime: HashMap::new(),
+            realtime_timestamp: None,
+            timezone,
+            time_regex,
         })
     }
 
+    /// Check out a pooled read-only connection for a single query.
+    fn connection(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        self.pool.get().map_err(|e| rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CANTOPEN),
+            Some(e.to_string()),
+        ))
+    }
+
+    /// Decode a GTFS-Realtime `FeedMessage` and index its `StopTimeUpdate`s by
+    /// `(trip_id, stop_id)`, replacing any previously loaded feed. Subsequent
+    /// `fetch_stops` calls overlay these predictions onto the static board.
+    /// Updates whose trip_id is not numeric, or that identify their stop only
+    /// by `stop_sequence`, are skipped.
+    pub fn apply_realtime(&mut self, feed: &[u8]) -> Result<(), Box<dyn Error>> {
+        let message = FeedMessage::decode(feed)?;
+        self.realtime_timestamp = message.header.timestamp.map(|t| t as i64);
+
+        let mut updates: HashMap<(u32, String), RealtimeUpdate> = HashMap::new();
+        for entity in message.entity {
+            let trip_update = match entity.trip_update {
+                Some(tu) => tu,
+                None => continue,
+            };
+            let trip_id = match trip_update.trip.trip_id.and_then(|id| id.parse::<u32>().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+            for stu in trip_update.stop_time_update {
+                let stop_id = match stu.stop_id {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let relationship = stu.schedule_relationship
+                    .and_then(|r| ScheduleRelationship::try_from(r).ok())
+                    .unwrap_or(ScheduleRelationship::Scheduled);
+                updates.insert((trip_id, stop_id), RealtimeUpdate {
+                    arrival: stu.arrival.and_then(event_to_prediction),
+                    departure: stu.departure.and_then(event_to_prediction),
+                    relationship,
+                });
+            }
+        }
+
+        self.realtime = updates;
+        Ok(())
+    }
+
     pub fn fetch_stations(&self, input: &str) -> Result<Vec<Station>> {
-        let mut stmt = self.db.prepare(&get_station_query(input))?;
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare(&get_station_query(input))?;
         let iter = stmt.query_map([], |row| {
             Ok(Station {
                 stop_id: row.get(0)?,
@@ -76,48 +300,243 @@ impl GTFSDatabase {
     }
 
     pub fn fetch_stops(
-        &self, stop_id: &str, board_type: BoardType, date_time: NaiveDateTime,
+        &self, stop_id: &str, board_type: BoardType, date_time: NaiveDateTime, filter: StopFilter,
     ) -> Result<Vec<Stop>, Box<dyn Error>> {
         if stop_id.is_empty() {
             Ok(Vec::new())
         } else {
-            let mut stmt = self.db.prepare(STOP_QUERY)?;
-            // let iter = stmt.query_map([stop_id], |row| self.map_stop(&row))?;
+            let conn = self.connection()?;
+            let mut stmt = conn.prepare(&get_stop_query(&filter))?;
+            let mut params = vec![format!("{}%", stop_id)];
+            if let Some(agency_ids) = &filter.agency_ids {
+                params.extend(agency_ids.iter().cloned());
+            }
             let iter = stmt.query_map(
-                [format!("{}%", stop_id)],
+                params_from_iter(params),
                 |row| self.map_stop(&row)
             )?;
-            let mut stops: Vec<Stop> = iter.map(|s| s.unwrap())
+            let raw: Vec<Stop> = iter.map(|s| s.unwrap()).collect();
+
+            // Expand frequency-based trips into virtual departures before
+            // filtering, so that trips defined only by headway in
+            // `frequencies.txt` flow through the same service/time filters.
+            let mut stops: Vec<Stop> = Vec::new();
+            for stop in raw {
+                match (self.frequencies.get(&stop.trip_id), self.frequency_starts.get(&stop.trip_id)) {
+                    (Some(freqs), Some(&first_dep)) => {
+                        for freq in freqs {
+                            // end_time is exclusive for both values; exact_times
+                            // only governs display: 1 is schedule-based (exact
+                            // spacing), 0 is frequency-based/approximate, flagged
+                            // so callers can render it as "~every N min".
+                            let exact = freq.exact_times == 1;
+                            let mut departure = freq.start_time;
+                            while departure < freq.end_time {
+                                let offset = departure - first_dep;
+                                stops.push(Stop {
+                                    arrival_time: stop.arrival_time + offset,
+                                    departure_time: stop.departure_time + offset,
+                                    exact,
+                                    ..stop.clone()
+                                });
+                                departure = departure + freq.headway;
+                            }
+                        }
+                    }
+                    _ => stops.push(stop),
+                }
+            }
+
+            // Overlay GTFS-Realtime predictions, unless the feed is absent or
+            // too stale to trust, before the service/time filters run. The
+            // board time is local, so localize it through the agency timezone
+            // to a real instant before comparing against the feed's epoch.
+            let now = self.timezone.from_local_datetime(&date_time).single()
+                .map(|dt| dt.timestamp());
+            let use_realtime = !self.realtime.is_empty() && match (now, self.realtime_timestamp) {
+                (Some(now), Some(ts)) => now - ts <= MAX_REALTIME_AGE_SECS,
+                _ => false,
+            };
+            let stops: Vec<Stop> = if use_realtime {
+                stops.into_iter().filter_map(|s| self.overlay_realtime(s)).collect()
+            } else {
+                stops
+            };
+
+            let mut stops: Vec<Stop> = stops.into_iter()
                 // F0: Remove unavailable service
                 .filter(|s| self.services.get(&s.service_id).unwrap().is_available(
                     &(date_time.date() - Duration::days(s.arrival_time.num_days()))
                 ))
                 // F1: Apply time filter
                 .filter(|s| s.is_after_adjusted_time(&board_type, &date_time))
+                // F2: Restrict to the requested route types, if any
+                .filter(|s| match &filter.route_types {
+                    Some(types) => types.contains(&s.route_type),
+                    None => true,
+                })
                 .collect();
 
             stops.sort_by(|a, b| a.get_adjusted_dt(&board_type, &date_time).cmp(
                 &b.get_adjusted_dt(&board_type, &date_time)));
 
+            // Cap the board once sorted so the earliest departures are kept.
+            if let Some(max_results) = filter.max_results {
+                stops.truncate(max_results);
+            }
+
             Ok(stops)
         }
     }
 
     pub fn fetch_trip(&self, trip_id: u32) -> Result<Vec<Stop>, Box<dyn Error>> {
-        let mut stmt = self.db.prepare(TRIP_QUERY)?;
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare(TRIP_QUERY)?;
         let iter = stmt.query_map([trip_id], |row| self.map_stop(&row))?;
 
         Ok(iter.map(|s| s.unwrap()).collect())
     }
 
+    /// Apply any realtime prediction for this `Stop` to its mapped times,
+    /// returning `None` for stops the feed marks as skipped (so callers can
+    /// hide them) and leaving static times untouched on `NO_DATA`. Keyed on
+    /// the row's own `stop_id`, not the board's prefix argument, so suffixed
+    /// platform stop_ids still match the feed.
+    fn overlay_realtime(&self, mut stop: Stop) -> Option<Stop> {
+        match self.realtime.get(&(stop.trip_id, stop.stop_id.clone())) {
+            Some(update) => match update.relationship {
+                ScheduleRelationship::Skipped => None,
+                ScheduleRelationship::NoData => Some(stop),
+                _ => {
+                    if let Some(prediction) = &update.arrival {
+                        stop.arrival_time =
+                            apply_prediction(stop.arrival_time, prediction, &self.timezone);
+                    }
+                    if let Some(prediction) = &update.departure {
+                        stop.departure_time =
+                            apply_prediction(stop.departure_time, prediction, &self.timezone);
+                    }
+                    Some(stop)
+                }
+            },
+            None => Some(stop),
+        }
+    }
+
+    /// Plan the earliest-arriving itinerary from `from_stop_id` to
+    /// `to_stop_id` leaving at `depart_at`, returned as the ordered legs of
+    /// the journey. Implemented with the Connection Scan Algorithm over the
+    /// connections of every service running on `depart_at`.
+    pub fn plan_journey(
+        &self, from_stop_id: &str, to_stop_id: &str, depart_at: NaiveDateTime,
+    ) -> Result<Vec<Stop>, Box<dyn Error>> {
+        let mut connections = self.build_connections(&depart_at.date())?;
+        connections.sort_by_key(|c| c.dep_time);
+
+        let depart = Duration::seconds(depart_at.time().num_seconds_from_midnight() as i64);
+        let infinity = Duration::max_value();
+
+        let mut earliest_arrival: HashMap<String, Duration> = HashMap::new();
+        earliest_arrival.insert(from_stop_id.to_string(), depart);
+        let mut journey_pointer: HashMap<String, Connection> = HashMap::new();
+
+        for conn in &connections {
+            // Connections depart in ascending order, so once they leave later
+            // than the best known arrival at the target it can only get worse.
+            if conn.dep_time > *earliest_arrival.get(to_stop_id).unwrap_or(&infinity) {
+                break;
+            }
+
+            let reachable = *earliest_arrival.get(&conn.dep_stop).unwrap_or(&infinity);
+            if conn.dep_time >= reachable
+                && conn.arr_time < *earliest_arrival.get(&conn.arr_stop).unwrap_or(&infinity) {
+                earliest_arrival.insert(conn.arr_stop.clone(), conn.arr_time);
+                journey_pointer.insert(conn.arr_stop.clone(), conn.clone());
+            }
+        }
+
+        // Walk the pointers backwards from the target to rebuild the legs.
+        let mut legs: Vec<Stop> = Vec::new();
+        let mut cursor = to_stop_id.to_string();
+        while let Some(conn) = journey_pointer.get(&cursor) {
+            legs.push(Stop {
+                arrival_time: conn.arr_time,
+                departure_time: conn.dep_time,
+                trip_id: conn.trip_id,
+                short_name: String::new(),
+                service_id: conn.service_id,
+                head_sign: conn.arr_stop.clone(),
+                route_type: RouteType::from_code(0),
+                stop_id: conn.arr_stop.clone(),
+                exact: true,
+            });
+            cursor = conn.dep_stop.clone();
+            if cursor == from_stop_id {
+                break;
+            }
+        }
+        legs.reverse();
+
+        Ok(legs)
+    }
+
+    /// Materialize the connection list from consecutive `stop_time` rows of
+    /// every trip whose service runs on `date`.
+    fn build_connections(&self, date: &NaiveDate) -> Result<Vec<Connection>, Box<dyn Error>> {
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare(CONNECTION_QUERY)?;
+        let mut rows = stmt.query([])?;
+
+        let mut connections: Vec<Connection> = Vec::new();
+        // (trip_id, service_id, stop_id, departure_time) of the previous row.
+        let mut prev: Option<(u32, u16, String, Option<Duration>)> = None;
+
+        while let Some(row) = rows.next()? {
+            let trip_id: u32 = row.get(0)?;
+            let service_id: u16 = row.get(1)?;
+            let stop_id: String = row.get(2)?;
+            let arrival = str_to_dur(&self.time_regex, row.get(3)?);
+            let departure = str_to_dur(&self.time_regex, row.get(4)?);
+
+            if let Some((prev_trip, prev_service, prev_stop, prev_dep)) = prev.take() {
+                let available = self.services.get(&prev_service)
+                    .map_or(false, |s| s.is_available(date));
+                // Skip legs whose endpoints have blank times (valid GTFS at
+                // non-timepoint stops) rather than panicking on them.
+                if let (true, true, Some(dep_time), Some(arr_time)) =
+                    (prev_trip == trip_id, available, prev_dep, arrival) {
+                    connections.push(Connection {
+                        dep_stop: prev_stop,
+                        arr_stop: stop_id.clone(),
+                        dep_time,
+                        arr_time,
+                        trip_id,
+                        service_id,
+                    });
+                }
+            }
+
+            prev = Some((trip_id, service_id, stop_id, departure));
+        }
+
+        Ok(connections)
+    }
+
     fn map_stop(&self, row: &Row) -> Result<Stop> {
+        // Either time may be blank at non-timepoint stops; fall back to the
+        // other (or zero) instead of panicking on valid GTFS.
+        let arrival = str_to_dur(&self.time_regex, row.get(0)?);
+        let departure = str_to_dur(&self.time_regex, row.get(1)?);
         Ok(Stop {
-            arrival_time: str_to_dur(&self.time_regex, row.get(0)?).unwrap(),
-            departure_time: str_to_dur(&self.time_regex, row.get(1)?).unwrap(),
+            arrival_time: arrival.or(departure).unwrap_or_else(Duration::zero),
+            departure_time: departure.or(arrival).unwrap_or_else(Duration::zero),
             trip_id: row.get(2)?,
             short_name: row.get(4)?,
             service_id: row.get(3)?,
             head_sign: row.get(5)?,
+            route_type: RouteType::from_code(row.get(6)?),
+            stop_id: row.get(8)?,
+            exact: true,
         })
     }
 }
@@ -174,3 +593,115 @@ pub fn fetch_services(db: &Connection) -> Result<HashMap<u16, Service>, Box<dyn
 
     Ok(map)
 }
+
+// Convert a realtime `StopTimeEvent` into a prediction, preferring an absolute
+// predicted time over a relative delay when both are present. The absolute
+// epoch is kept raw and localized at apply time.
+fn event_to_prediction(event: StopTimeEvent) -> Option<Prediction> {
+    if let Some(time) = event.time {
+        Some(Prediction::Absolute(time))
+    } else {
+        event.delay.map(|d| Prediction::Delay(Duration::seconds(d as i64)))
+    }
+}
+
+// Fold a prediction onto a scheduled time: add delays directly, and for an
+// absolute epoch substitute the agency-local time of day so boards are not
+// shifted by the UTC offset.
+fn apply_prediction(scheduled: Duration, prediction: &Prediction, timezone: &Tz) -> Duration {
+    match prediction {
+        Prediction::Absolute(epoch) => match Utc.timestamp_opt(*epoch, 0).single() {
+            Some(instant) => {
+                let local = instant.with_timezone(timezone);
+                Duration::seconds(local.time().num_seconds_from_midnight() as i64)
+            }
+            None => scheduled,
+        },
+        Prediction::Delay(delay) => scheduled + *delay,
+    }
+}
+
+//Called once at startup
+//Feeds are typically single-timezone; the first agency timezone is used to
+//localize absolute realtime predictions, defaulting to UTC when unset.
+pub fn fetch_timezone(db: &Connection) -> Result<Tz, Box<dyn Error>> {
+    let timezone: Option<String> = db.query_row(
+        "SELECT timezone FROM agency WHERE timezone IS NOT NULL LIMIT 1;",
+        [],
+        |row| row.get(0),
+    ).optional()?;
+
+    Ok(timezone.and_then(|tz| tz.parse().ok()).unwrap_or(Tz::UTC))
+}
+
+//Called once at startup alongside the service map
+pub fn fetch_frequencies(
+    db: &Connection, time_regex: &Regex,
+) -> Result<HashMap<u32, Vec<Frequency>>, Box<dyn Error>> {
+    let mut stmt = db.prepare(FREQUENCY_QUERY)?;
+
+    let mut rows = stmt.query([])?;
+    let mut map: HashMap<u32, Vec<Frequency>> = HashMap::new();
+
+    while let Some(row) = rows.next()? {
+        let trip_id = row.get(0)?;
+
+        // exact_times is optional in frequencies.txt; the importer stores a
+        // blank cell as "", so default anything non-integer to 0 rather than
+        // failing the whole database open.
+        let exact_times = match row.get::<usize, Value>(4)? {
+            Value::Integer(value) => value as u8,
+            Value::Text(value) => value.trim().parse().unwrap_or(0),
+            _ => 0,
+        };
+
+        let frequency = Frequency {
+            start_time: str_to_dur(time_regex, row.get(1)?).unwrap(),
+            end_time: str_to_dur(time_regex, row.get(2)?).unwrap(),
+            headway: Duration::seconds(row.get::<usize, i64>(3)?),
+            exact_times,
+        };
+
+        map.entry(trip_id).or_default().push(frequency);
+    }
+
+    Ok(map)
+}
+
+//Called once at startup, after the frequency map is known
+//Precomputes each frequency-based trip's first departure so expansion in
+//`fetch_stops` is a map lookup rather than a per-stop MIN() query.
+pub fn fetch_frequency_starts(
+    db: &Connection, time_regex: &Regex, frequencies: &HashMap<u32, Vec<Frequency>>,
+) -> Result<HashMap<u32, Duration>, Box<dyn Error>> {
+    let mut starts: HashMap<u32, Duration> = HashMap::new();
+    if frequencies.is_empty() {
+        return Ok(starts);
+    }
+
+    let ids = frequencies.keys()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    // Times are text (hours may be one digit, blank at non-timepoint stops),
+    // so take the first parseable departure in stop_sequence order.
+    let query = format!(
+        "SELECT trip_id, departure_time FROM stop_time WHERE trip_id IN ({}) \
+        ORDER BY trip_id, stop_sequence;",
+        ids
+    );
+
+    let mut stmt = db.prepare(&query)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let trip_id: u32 = row.get(0)?;
+        if starts.contains_key(&trip_id) {
+            continue;
+        }
+        if let Some(departure) = str_to_dur(time_regex, row.get(1)?) {
+            starts.insert(trip_id, departure);
+        }
+    }
+
+    Ok(starts)
+}